@@ -1,9 +1,27 @@
 //! Repackage `.crate` files under a different crate name.
 //!
 //! This crate provides [`repackage::dot_crate`](dot_crate), which repackages a `.crate` file so
-//! that it exports the same crate under a different name. It replaces the `name` attribute in
-//! `Cargo.toml`, and also rewrites references to the old name in the various `.rs` files that live
-//! outside of `src/` (those in `src/` use `crate::`).
+//! that it exports the same crate under a different name. It replaces the `package.name`
+//! attribute in both `Cargo.toml` and the verbatim `Cargo.toml.orig` that ships alongside it,
+//! along with any explicit `[lib]`/`[[bin]]`/`[[test]]`/`[[example]]`/`[[bench]]` target `name`
+//! that still points at the old name (the name consumers actually link against), and also
+//! rewrites references to the old name in the various `.rs` files that live outside of `src/`
+//! (those in `src/` use `crate::`).
+//!
+//! Once a crate has been repackaged, anything that still depends on it by its old name needs
+//! patching too: [`rename_dependency`] rewrites a consumer `.crate`'s dependency tables and
+//! source to point at the new name instead.
+//!
+//! If you also need to bump the version or retarget the crate's own dependencies at an alternate
+//! registry (typical when repackaging ahead of publishing somewhere other than crates.io), build
+//! a [`Repackage`] instead of calling `dot_crate` directly.
+//!
+//! If you'd rather operate on an already-open reader/writer than a filesystem path -- say, bytes
+//! received over a socket, or in a pipeline that never wants the intermediate `.crate` to touch
+//! disk -- call [`dot_crate_stream`] instead, which [`dot_crate`] is itself a thin wrapper around.
+//! It also returns a [`RepackageReport`] detailing exactly what got renamed, so callers can log,
+//! audit, or flag an unexpectedly large number of `.rs` substitutions as a sign the heuristic
+//! rewrite over-matched.
 //!
 //! # Rewriting .rs files
 //!
@@ -11,9 +29,10 @@
 //! Consumers of a `.crate` file likely only care about the exported library, which only ever
 //! refers to itself using paths starting with `crate::` or `::`, not including the name. Tests and
 //! binaries do have to name the library crate, but are usually not used by downstream consumers of
-//! the `.crate`. But, _just_ in case, this crate tries to modify those files as well using some
-//! simple string replacement. It's brittle though, so you might only get so far with that approach
-//! if you make heavy use of non-library artifacts in the produced `.crate` files.
+//! the `.crate`. But, _just_ in case, this crate tries to modify those files as well, using a
+//! token-aware rewrite (see [`rewrite`]) that only touches identifiers that are actually in
+//! path-head position, so it can't be confused by a struct field or method call that happens to
+//! share a name with the crate.
 #![warn(missing_docs, broken_intra_doc_links)]
 
 use anyhow::Context;
@@ -21,6 +40,8 @@ use cargo_toml::Manifest;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
+mod rewrite;
+
 /// Repackage the crate contained in the `.crate` tarball at `dot_crate` as `new_name`.
 ///
 /// Pass in the old crate name to verify that the crate you are repackaging is in fact the one you
@@ -31,6 +52,11 @@ use std::path::{Path, PathBuf};
 /// The repackaged file will end up next to the current `.crate` file with the crate name replaced
 /// appropriately. In other words, if you are replacing `foo` with `bar`, and give the input file
 /// `baz/foo-0.1.0.crate`, the repackaged crate file will be `baz/bar-0.1.0.crate`.
+///
+/// This is a thin wrapper around [`dot_crate_stream`] for the common case of a plain rename on
+/// disk. Use [`Repackage`] instead if you also need to bump the version or retarget dependencies
+/// at an alternate registry, or call [`dot_crate_stream`] directly if you'd rather operate on an
+/// already-open reader/writer (or want the [`RepackageReport`] of what was actually rewritten).
 pub fn dot_crate(
     dot_crate: impl AsRef<Path>,
     old_name: Option<&str>,
@@ -38,8 +64,6 @@ pub fn dot_crate(
 ) -> anyhow::Result<()> {
     let dot_crate = dot_crate.as_ref();
 
-    // We want to use the same file path, but with the crate name replaced.
-    // To do that we first need to extract the file name portion of the .crate path:
     let old_fn = dot_crate
         .file_name()
         .ok_or_else(|| anyhow::anyhow!(".crate file path '{}' is not a file", dot_crate.display()))?
@@ -50,109 +74,558 @@ pub fn dot_crate(
                 dot_crate.display()
             )
         })?;
+    let old_name = infer_old_name(old_fn, old_name)?;
 
-    // Next, we verify that the .crate file is actually for the crate the user wanted to replace.
-    // Otherwise, we might be repackaging some entirely different crate. Now, that will also be
-    // caught once we get to the Cargo.toml file and verify its name, but if we can catch a mistake
-    // sooner, that's better.
-    //
-    // Let's also handle the somewhat unlikely (but possible) prefix problem: Imagine someone wants
-    // to rewrite the net crate to net2, but then pass us the crate file:
-    //
-    //     netscape-0.1.0.crate
-    //
-    // Sure, it _starts_ with net, but it's probably not the .crate file they intended to pass us.
-    //
-    // The trick is to look for the first . (which cannot appear in crate names), and walk
-    // _backwards_ from there.
-    let mut prefix = None;
-    if let Some(dot) = old_fn.find('.') {
-        if let Some(dash) = old_fn[..dot].rfind('-') {
-            let name = &old_fn[..dash];
-            let major = &old_fn[(dash + 1)..dot];
-            if !name.is_empty() && !major.is_empty() && major.chars().all(|c| c.is_ascii_digit()) {
-                prefix = Some(name);
-            }
+    // The repackaged filename is `{new_name}-{version}.crate`, so peel the old name (and the
+    // surrounding `-`/`.crate`) off the original filename to recover the version.
+    let old_version = old_fn
+        .strip_prefix(old_name)
+        .and_then(|s| s.strip_prefix('-'))
+        .and_then(|s| s.strip_suffix(".crate"))
+        .ok_or_else(|| anyhow::anyhow!("failed to parse version out of '{}'", old_fn))?;
+
+    let repackaged_fn = format!("{}-{}.crate", new_name, old_version);
+    let repackaged_path = dot_crate.with_file_name(repackaged_fn);
+    let input = std::fs::File::open(dot_crate)?;
+    let output = std::fs::File::create(&repackaged_path)?;
+
+    if let Err(e) = dot_crate_stream(input, output, old_name, new_name) {
+        let _ = std::fs::remove_file(&repackaged_path);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// A repackaging operation, for when a plain rename (see [`dot_crate`]) isn't enough.
+///
+/// Repackaging usually precedes publishing to a private/alternate registry, which often also
+/// requires bumping the version (since re-publishing a renamed crate under a fresh registry
+/// almost always requires a new version string) and pointing the crate's own dependencies at that
+/// alternate registry rather than crates.io.
+///
+/// Build one with [`Repackage::new`], configure it with [`Repackage::old_name`],
+/// [`Repackage::version`] and/or [`Repackage::registry`], then call [`Repackage::run`].
+pub struct Repackage {
+    old_name: Option<String>,
+    new_name: String,
+    new_version: Option<String>,
+    registry_name: Option<String>,
+    registry_index: Option<String>,
+}
+
+impl Repackage {
+    /// Start repackaging a crate under `new_name`.
+    pub fn new(new_name: impl Into<String>) -> Self {
+        Repackage {
+            old_name: None,
+            new_name: new_name.into(),
+            new_version: None,
+            registry_name: None,
+            registry_index: None,
         }
     }
-    if old_name.is_some() && prefix != old_name {
-        anyhow::bail!(
-            ".crate file '{}' does not match given old name '{}'",
-            dot_crate.display(),
-            old_name
-                .as_ref()
-                .expect("check for is_some in if conditional"),
-        );
+
+    /// Verify that the `.crate` file being repackaged is in fact `old_name`, rather than inferring
+    /// it from the `.crate` file's name.
+    pub fn old_name(mut self, old_name: impl Into<String>) -> Self {
+        self.old_name = Some(old_name.into());
+        self
     }
-    let old_name = old_name
-        .or(prefix)
-        .ok_or_else(|| anyhow::anyhow!("failed to infer current crate name"))?;
 
-    let repackaged_fn = old_fn.replace(old_name, new_name);
+    /// Bump `package.version` to `new_version` in the repackaged manifest, and reflect the new
+    /// version in the archive's base directory and the output filename.
+    pub fn version(mut self, new_version: impl Into<String>) -> Self {
+        self.new_version = Some(new_version.into());
+        self
+    }
+
+    /// Set every dependency's `registry` (an alias configured in `.cargo/config.toml`) and/or
+    /// `registry-index` (a direct index URL), so the repackaged crate resolves its dependencies
+    /// from an alternate registry rather than crates.io.
+    pub fn registry(
+        mut self,
+        name: Option<impl Into<String>>,
+        index: Option<impl Into<String>>,
+    ) -> Self {
+        self.registry_name = name.map(Into::into);
+        self.registry_index = index.map(Into::into);
+        self
+    }
+
+    /// Repackage the crate contained in the `.crate` tarball at `dot_crate` with the options
+    /// configured so far.
+    pub fn run(self, dot_crate: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dot_crate = dot_crate.as_ref();
+
+        // We want to use the same file path, but with the crate name (and, potentially, version)
+        // replaced. To do that we first need to extract the file name portion of the .crate path:
+        let old_fn = dot_crate
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!(".crate file path '{}' is not a file", dot_crate.display()))?
+            .to_str()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    ".crate file path '{}' is not valid utf-8",
+                    dot_crate.display()
+                )
+            })?;
+
+        // Next, we verify that the .crate file is actually for the crate the user wanted to
+        // replace. Otherwise, we might be repackaging some entirely different crate. Now, that
+        // will also be caught once we get to the Cargo.toml file and verify its name, but if we
+        // can catch a mistake sooner, that's better.
+        let old_name = infer_old_name(old_fn, self.old_name.as_deref())?;
+
+        // Both the tar path prefix and the repackaged filename are `{name}-{version}`, so now
+        // that we know the old name for sure we can peel it (and the surrounding `-`/`.crate`)
+        // off to recover the version that's already baked into the filename.
+        let old_version = old_fn
+            .strip_prefix(old_name)
+            .and_then(|s| s.strip_prefix('-'))
+            .and_then(|s| s.strip_suffix(".crate"))
+            .ok_or_else(|| anyhow::anyhow!("failed to parse version out of '{}'", old_fn))?;
+        let new_name = self.new_name.as_str();
+        let new_version = self.new_version.as_deref().unwrap_or(old_version);
+
+        let repackaged_fn = format!("{}-{}.crate", new_name, new_version);
+        let repackaged_path = dot_crate.with_file_name(repackaged_fn);
+        let repackaged = std::fs::File::create(&repackaged_path)?;
+        let dot_crate_path = dot_crate;
+        let dot_crate = std::fs::File::open(&dot_crate_path)?;
+
+        // https://github.com/rust-lang/cargo/blob/8e075c9cab41eb1ed6222f819924999476477f2e/src/cargo/ops/cargo_package.rs#L481
+        let dot_crate = flate2::read::GzDecoder::new(dot_crate);
+        let dot_crate = tar::Archive::new(dot_crate);
+        let repackaged = flate2::GzBuilder::new().write(repackaged, flate2::Compression::best());
+        let repackaged = tar::Builder::new(repackaged);
+
+        // We've got to be a little careful with replacements in .rs files.
+        //
+        // Imagine that a crate is called toml, and there's a struct field in the program called
+        // toml. We obviously don't want to replace that, as it may be referenced elsewhere (might
+        // even be a public field!). The same concern applies to both prefixes and suffixes.
+        //
+        // Luckily, crate names should only really show up in paths. That is, as crate_name::. It
+        // can also show up as "use crate_name;" or "extern crate crate_name;" (possibly with an
+        // alias). [`rewrite::rewrite_path_heads`] handles all of these by walking the file as a
+        // token stream rather than matching on substrings, so it isn't fooled by tabs, a macro
+        // invoked by its full path at the top level of a file, or a full path used as a function
+        // argument (`.any(toml::is_foo)`), all of which would trip up a plain string replace.
+        let old_ident = old_name.replace('-', "_");
+        let new_ident = new_name.replace('-', "_");
+
+        // We also need to modify all paths inside the archive to start at new-name-0.1.0/ rather
+        // than old-name-0.1.0/. This is simple enough, since we already have the name and version
+        // on both sides.
+        let old_base_dir = PathBuf::from(format!("{}-{}", old_name, old_version));
+        let new_base_dir = PathBuf::from(format!("{}-{}", new_name, new_version));
+
+        let summary = match repackage_archive(
+            dot_crate,
+            repackaged,
+            RepackageOptions {
+                old_base_dir: &old_base_dir,
+                new_base_dir: &new_base_dir,
+                // Binaries, tests, etc. will contain old_name:: paths, which we need to re-write
+                // so that they still work after we change the top-level crate name. We _could_
+                // try to inject `extern crate old_name as new_name`, but that can only be
+                // injected at the top-level crate entry point (and only after //!, #!, /*!,
+                // etc.), so rewriting the path heads in place is easier.
+                //
+                // Now, _technically_ this replacement shouldn't matter, since we're modifying a
+                // package in a `.crate`, so any consumers should only be using the `lib` of the
+                // current package anyway. And `lib` lives in `src/` (let's go ahead and assume
+                // they haven't changed that) and refers to the current crate using `::` or
+                // `crate::`, neither of which contain the current crate's name. But, we go for
+                // best effort anyway.
+                rewrite_rs: |path: &Path| !path.starts_with("src"),
+                edit_manifest: |manifest: &mut Manifest| {
+                    let p = manifest.package.as_mut().ok_or_else(|| {
+                        anyhow::anyhow!("Cargo.toml in .crate file does not contain a package")
+                    })?;
+                    if &p.name != old_name {
+                        anyhow::bail!(
+                            "crate name in .crate ('{}') file did not match given name ('{}')",
+                            p.name,
+                            old_name
+                        );
+                    }
+                    p.name = new_name.to_string();
+                    if self.new_version.is_some() {
+                        p.version = cargo_toml::Inheritable::Set(new_version.to_string());
+                    }
+
+                    // `package.name` is what downstream `Cargo.toml`s reference in their
+                    // `[dependencies]`, but what `extern crate`/`use` actually resolves against
+                    // is the *lib target* name, which defaults to `package.name` but can be
+                    // overridden with an explicit `[lib] name = "..."`. The same goes for
+                    // `[[bin]]`/`[[test]]`/`[[example]]`/`[[bench]]` targets. If we don't also
+                    // rewrite those, a consumer doing `use old_name::...` still breaks even
+                    // though `package.name` now says `new_name`.
+                    if let Some(lib) = manifest.lib.as_mut() {
+                        rename_target(lib, old_name, new_name);
+                    }
+                    for product in manifest
+                        .bin
+                        .iter_mut()
+                        .chain(manifest.test.iter_mut())
+                        .chain(manifest.example.iter_mut())
+                        .chain(manifest.bench.iter_mut())
+                    {
+                        rename_target(product, old_name, new_name);
+                    }
+
+                    if self.registry_name.is_some() || self.registry_index.is_some() {
+                        for dep in manifest
+                            .dependencies
+                            .values_mut()
+                            .chain(manifest.dev_dependencies.values_mut())
+                            .chain(manifest.build_dependencies.values_mut())
+                        {
+                            set_registry(
+                                dep,
+                                self.registry_name.as_deref(),
+                                self.registry_index.as_deref(),
+                            )?;
+                        }
+                    }
+
+                    Ok(Vec::new())
+                },
+                old_ident: &old_ident,
+                new_ident: &new_ident,
+            },
+        ) {
+            Ok(summary) => summary,
+            Err(e) => {
+                let _ = std::fs::remove_file(&repackaged_path);
+                return Err(e);
+            }
+        };
+
+        if !summary.got_cargo_toml {
+            let _ = std::fs::remove_file(&repackaged_path);
+            anyhow::bail!(
+                ".crate file {} did not contain a Cargo.toml file",
+                dot_crate_path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Patch a consumer `.crate` so that it depends on `new_dep` instead of `old_dep`.
+///
+/// Repackaging a crate (see [`dot_crate`]) changes what name it's exported under, but every
+/// crate in the dependency tree that still names the old dependency in its own `Cargo.toml` and
+/// source won't build against it. This rewrites the `[dependencies]`, `[dev-dependencies]`, and
+/// `[build-dependencies]` tables of the given `.crate`, renaming the `old_dep` key to `new_dep`
+/// (keeping its version and features), and then rewrites every `.rs` file in the archive --
+/// including those under `src/`, since dependency paths there do use the dependency's name -- so
+/// that `old_dep::` paths keep resolving.
+///
+/// Unlike [`dot_crate`], the package's own name is untouched, so the repackaged file can't simply
+/// replace the old name in the filename; instead it's written next to the input with a
+/// `-patched` suffix. In other words, `baz/consumer-0.1.0.crate` becomes
+/// `baz/consumer-0.1.0-patched.crate`.
+pub fn rename_dependency(
+    dot_crate: impl AsRef<Path>,
+    old_dep: &str,
+    new_dep: &str,
+) -> anyhow::Result<()> {
+    let dot_crate = dot_crate.as_ref();
+
+    let old_fn = dot_crate
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!(".crate file path '{}' is not a file", dot_crate.display()))?
+        .to_str()
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                ".crate file path '{}' is not valid utf-8",
+                dot_crate.display()
+            )
+        })?;
+
+    let mut repackaged_fn = PathBuf::from(old_fn);
+    repackaged_fn.set_extension("");
+    let repackaged_fn = format!("{}-patched.crate", repackaged_fn.display());
     let repackaged_path = dot_crate.with_file_name(repackaged_fn);
     let repackaged = std::fs::File::create(&repackaged_path)?;
     let dot_crate_path = dot_crate;
     let dot_crate = std::fs::File::open(&dot_crate_path)?;
 
-    // https://github.com/rust-lang/cargo/blob/8e075c9cab41eb1ed6222f819924999476477f2e/src/cargo/ops/cargo_package.rs#L481
     let dot_crate = flate2::read::GzDecoder::new(dot_crate);
-    let mut dot_crate = tar::Archive::new(dot_crate);
+    let dot_crate = tar::Archive::new(dot_crate);
     let repackaged = flate2::GzBuilder::new().write(repackaged, flate2::Compression::best());
-    let mut repackaged = tar::Builder::new(repackaged);
+    let repackaged = tar::Builder::new(repackaged);
 
-    // We've got to be a little careful with replacements in .rs files.
-    //
-    // Imagine that a crate is called toml, and there's a struct field in the program called toml.
-    // We obviously don't want to replace that, as it may be referenced elsewhere (might even be a
-    // public field!). The same concern applies to both prefixes and suffixes.
-    //
-    // Luckily, crate names should only really show up in paths. That is, as crate_name::.
-    // Teeechnically it can also show up as "use crate_name;" or "extern crate crate_name;", or
-    // _even_ "extern crate crate_name as foobar;", but we're going to ignore those here since they
-    // first two are trivial to fix in the code, and the last will break our renaming anyway.
-    //
-    // And for good measure, we also need to make sure the path is preceeded by a space, otherwise
-    // our `toml` example would also rewrite
-    //
-    //     use foo_toml::bar;
-    //
-    // and
-    //
-    //     use foo::toml::bar;
-    //
-    // which we don't want. This will also still work in cases like toml::some_func(a).
-    //
-    // Unfortunately, it will _not_ work for anyone who tries to be fancy, such as by using tabs
-    // over spaces, invoking macros by their full path at the top level of the file, or providing
-    // full paths to functions and types as the first argument to a function
-    // (`.any(toml::is_foo)`). Those _should_ be rare though, and keep in mind this does not apply
-    // for files in `src/`, so let's consider it good enough until someone complains.
-    let from = format!(" {}::", old_name.replace('-', "_"));
-    let to = format!(" {}::", new_name.replace('-', "_"));
-
-    // We also need to modify all paths inside the archive to start at new-name-0.1.0/ rather than
-    // old-name-0.1.0/. This is simple enough as we're replacing the path wholesale.
-    let old_base_dir = {
+    // The package itself isn't being renamed here, so the archive's base directory (derived from
+    // the package name and version) doesn't change.
+    let base_dir = {
         let mut d = PathBuf::from(old_fn);
         d.set_extension("");
         d
     };
-    let new_base_dir = {
-        let mut d = PathBuf::from(old_fn.replace(old_name, new_name));
-        d.set_extension("");
-        d
+
+    let old_ident = old_dep.replace('-', "_");
+    let new_ident = new_dep.replace('-', "_");
+
+    let summary = match repackage_archive(
+        dot_crate,
+        repackaged,
+        RepackageOptions {
+            old_base_dir: &base_dir,
+            new_base_dir: &base_dir,
+            // Dependency paths can show up anywhere in the crate, including in src/, unlike the
+            // crate's own name (which src/ only ever refers to via `crate::` or `::`).
+            rewrite_rs: |_path: &Path| true,
+            edit_manifest: |manifest: &mut Manifest| {
+                let mut found = false;
+                for deps in [
+                    &mut manifest.dependencies,
+                    &mut manifest.dev_dependencies,
+                    &mut manifest.build_dependencies,
+                ] {
+                    if let Some(dep) = deps.remove(old_dep) {
+                        deps.insert(new_dep.to_string(), dep);
+                        found = true;
+                    }
+                }
+                if !found {
+                    anyhow::bail!(
+                        "'{}' is not a dependency, dev-dependency, or build-dependency of this crate",
+                        old_dep
+                    );
+                }
+                Ok(Vec::new())
+            },
+            old_ident: &old_ident,
+            new_ident: &new_ident,
+        },
+    ) {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = std::fs::remove_file(&repackaged_path);
+            return Err(e);
+        }
+    };
+
+    if !summary.got_cargo_toml {
+        let _ = std::fs::remove_file(&repackaged_path);
+        anyhow::bail!(
+            ".crate file {} did not contain a Cargo.toml file",
+            dot_crate_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// A summary of what [`dot_crate_stream`] actually rewrote, returned so callers can log, audit, or
+/// flag an unexpectedly large number of `.rs` substitutions as a sign that the heuristic rewrite
+/// over-matched.
+#[derive(Debug)]
+pub struct RepackageReport {
+    /// The crate name the archive was repackaged from.
+    pub old_name: String,
+    /// The crate name the archive was repackaged to.
+    pub new_name: String,
+    /// The archive's base directory before repackaging, e.g. `foo-0.1.0`.
+    pub old_base_dir: PathBuf,
+    /// The archive's base directory after repackaging, e.g. `bar-0.1.0`.
+    pub new_base_dir: PathBuf,
+    /// The kind (e.g. `"lib"`, `"bin"`) of each target whose explicit `name` was rewritten, one
+    /// entry per renamed target.
+    pub renamed_targets: Vec<String>,
+    /// Each `.rs` file that had at least one path-head substitution, and how many.
+    pub rewritten_files: Vec<(PathBuf, usize)>,
+}
+
+/// Repackage the `.crate` tarball read from `input` as `new_name`, writing the result to `output`,
+/// without touching the filesystem. [`dot_crate`] is a thin wrapper around this for the common
+/// case of repackaging a file on disk.
+///
+/// Unlike [`dot_crate`], `old_name` can't be inferred from a filename here, so it must be given
+/// explicitly.
+pub fn dot_crate_stream<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    old_name: &str,
+    new_name: &str,
+) -> anyhow::Result<RepackageReport> {
+    // We need to know the archive's base directory before we can tell `repackage_archive` what to
+    // relocate it to, but that's only visible once we start walking entries, and `tar::Archive`
+    // consumes its reader as it walks them. Since `R` isn't guaranteed to be seekable, we buffer
+    // the (compressed) input once and make two passes over it: one to peek at the first entry's
+    // path, and one for the real rewrite.
+    let mut compressed = Vec::new();
+    input
+        .read_to_end(&mut compressed)
+        .context("read .crate file into memory")?;
+
+    let old_base_dir = {
+        let peek = flate2::read::GzDecoder::new(&compressed[..]);
+        let mut peek = tar::Archive::new(peek);
+        let first = peek
+            .entries()
+            .context("walk entries from .crate file")?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!(".crate file contains no entries"))?
+            .context("walk entry from .crate file")?;
+        let path = first.path().context("get .crate file entry path")?;
+        let base_dir = path
+            .components()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!(".crate file entry has an empty path"))?;
+        PathBuf::from(base_dir.as_os_str())
     };
 
+    // The tar path prefix is `{name}-{version}`, so peel off old_name (and the surrounding `-`)
+    // to recover the version that's already baked into it.
+    let version = old_base_dir
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!(".crate file base directory is not valid utf-8"))?
+        .strip_prefix(old_name)
+        .and_then(|s| s.strip_prefix('-'))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                ".crate file base directory '{}' does not match given old name '{}'",
+                old_base_dir.display(),
+                old_name
+            )
+        })?;
+    let new_base_dir = PathBuf::from(format!("{}-{}", new_name, version));
+
+    let archive = flate2::read::GzDecoder::new(&compressed[..]);
+    let archive = tar::Archive::new(archive);
+    let builder = flate2::GzBuilder::new().write(output, flate2::Compression::best());
+    let builder = tar::Builder::new(builder);
+
+    let old_ident = old_name.replace('-', "_");
+    let new_ident = new_name.replace('-', "_");
+
+    let summary = repackage_archive(
+        archive,
+        builder,
+        RepackageOptions {
+            old_base_dir: &old_base_dir,
+            new_base_dir: &new_base_dir,
+            rewrite_rs: |path: &Path| !path.starts_with("src"),
+            edit_manifest: |manifest: &mut Manifest| {
+                let p = manifest.package.as_mut().ok_or_else(|| {
+                    anyhow::anyhow!("Cargo.toml in .crate file does not contain a package")
+                })?;
+                if p.name != old_name {
+                    anyhow::bail!(
+                        "crate name in .crate file ('{}') did not match given name ('{}')",
+                        p.name,
+                        old_name
+                    );
+                }
+                p.name = new_name.to_string();
+
+                let mut renamed_targets = Vec::new();
+                if let Some(lib) = manifest.lib.as_mut() {
+                    if rename_target(lib, old_name, new_name) {
+                        renamed_targets.push("lib".to_string());
+                    }
+                }
+                for (kind, product) in manifest
+                    .bin
+                    .iter_mut()
+                    .map(|p| ("bin", p))
+                    .chain(manifest.test.iter_mut().map(|p| ("test", p)))
+                    .chain(manifest.example.iter_mut().map(|p| ("example", p)))
+                    .chain(manifest.bench.iter_mut().map(|p| ("bench", p)))
+                {
+                    if rename_target(product, old_name, new_name) {
+                        renamed_targets.push(kind.to_string());
+                    }
+                }
+
+                Ok(renamed_targets)
+            },
+            old_ident: &old_ident,
+            new_ident: &new_ident,
+        },
+    )?;
+
+    if !summary.got_cargo_toml {
+        anyhow::bail!(".crate file did not contain a Cargo.toml file");
+    }
+
+    Ok(RepackageReport {
+        old_name: old_name.to_string(),
+        new_name: new_name.to_string(),
+        old_base_dir,
+        new_base_dir,
+        renamed_targets: summary.renamed_targets,
+        rewritten_files: summary.rewritten_files,
+    })
+}
+
+/// What actually happened while [`repackage_archive`] walked an archive.
+struct ArchiveSummary {
+    /// Whether a `Cargo.toml` (as opposed to just `Cargo.toml.orig`) was found.
+    got_cargo_toml: bool,
+    /// Kinds (e.g. `"lib"`, `"bin"`) of the targets `edit_manifest` reported renaming, taken from
+    /// whichever call handled the canonical `Cargo.toml`.
+    renamed_targets: Vec<String>,
+    /// Each `.rs` file that had at least one path-head substitution, and how many.
+    rewritten_files: Vec<(PathBuf, usize)>,
+}
+
+/// The parts of [`repackage_archive`] that vary by caller, bundled up so the function itself
+/// doesn't trip `clippy::too_many_arguments`.
+struct RepackageOptions<'a, F, M> {
+    old_base_dir: &'a Path,
+    new_base_dir: &'a Path,
+    /// Called with each `.rs` file's path relative to the archive's base directory (e.g.
+    /// `src/lib.rs`); if it returns `true`, the file's path heads are rewritten from `old_ident`
+    /// to `new_ident`.
+    rewrite_rs: F,
+    /// Called with each `Cargo.toml`/`Cargo.toml.orig` found, to make caller-specific edits.
+    /// Returns the kind (e.g. `"lib"`, `"bin"`) of each target it renamed.
+    edit_manifest: M,
+    old_ident: &'a str,
+    new_ident: &'a str,
+}
+
+/// Walk every entry in `archive`, relocating it from `opts.old_base_dir` to `opts.new_base_dir`,
+/// rewrite any `Cargo.toml`/`Cargo.toml.orig` found via `opts.edit_manifest` (which reports back
+/// the kind of each target it renamed), rewrite `.rs` sources for which `opts.rewrite_rs` returns
+/// `true` using the token-aware [`rewrite::rewrite_path_heads`] (from `opts.old_ident` to
+/// `opts.new_ident`), and copy everything else through unmodified, writing the result to
+/// `builder`.
+fn repackage_archive<R: Read, W: Write, F, M>(
+    mut archive: tar::Archive<R>,
+    mut builder: tar::Builder<W>,
+    opts: RepackageOptions<F, M>,
+) -> anyhow::Result<ArchiveSummary>
+where
+    F: Fn(&Path) -> bool,
+    M: FnMut(&mut Manifest) -> anyhow::Result<Vec<String>>,
+{
+    let RepackageOptions {
+        old_base_dir,
+        new_base_dir,
+        rewrite_rs,
+        mut edit_manifest,
+        old_ident,
+        new_ident,
+    } = opts;
+
     let mut got_cargo_toml = false;
+    let mut renamed_targets = Vec::new();
+    let mut rewritten_files = Vec::new();
     let mut file_bytes = String::new();
-    for file in dot_crate
-        .entries()
-        .context("walk entries from .crate file")?
-    {
+    for file in archive.entries().context("walk entries from .crate file")? {
         let mut file = file.context("walk entry from .crate file")?;
         let mut header = file.header().clone();
         let path = file.path().context("get .crate file entry path")?;
-        let sub_path = path.strip_prefix(&old_base_dir).map_err(|_| {
+        let sub_path = path.strip_prefix(old_base_dir).map_err(|_| {
             anyhow::anyhow!(
                 ".crate contained entry not under old crate subdir: {}",
                 path.display()
@@ -160,28 +633,29 @@ pub fn dot_crate(
         })?;
         let path = new_base_dir.join(sub_path);
 
-        if path.file_name() == Some(std::ffi::OsStr::new("Cargo.toml")) {
-            // To avoid reading into memory we need:
-            // https://github.com/alexcrichton/toml-rs/issues/215
+        // Own the file name rather than borrowing `path`, since `path` itself gets moved into
+        // `append_data` below while we still need to know which file this was afterwards.
+        let file_name = path
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .map(str::to_string);
+        if file_name.as_deref() == Some("Cargo.toml") || file_name.as_deref() == Some("Cargo.toml.orig")
+        {
+            // A `.crate` produced by `cargo package` ships both the normalized `Cargo.toml` and,
+            // verbatim, the pre-publish `Cargo.toml.orig` that `cargo package` read it from. Both
+            // need the same edits, or anything that reads `Cargo.toml.orig` (or re-verifies the
+            // package) will still see the old state.
             let mut toml_bytes = Vec::with_capacity(file.size() as usize);
             file.read_to_end(&mut toml_bytes)
-                .context("read Cargo.toml from .crate file")?;
-            let mut manifest =
-                Manifest::from_slice(&toml_bytes).context("parse Cargo.toml from .crate file")?;
+                .with_context(|| format!("read {} from .crate file", file_name.as_deref().unwrap()))?;
+            let mut manifest = Manifest::from_slice(&toml_bytes)
+                .with_context(|| format!("parse {} from .crate file", file_name.as_deref().unwrap()))?;
             if manifest.workspace.is_some() {
                 anyhow::bail!(".crate file is a workspace, so is not packaged");
             }
-            let p = manifest.package.as_mut().ok_or_else(|| {
-                anyhow::anyhow!("Cargo.toml in .crate file does not contain a package")
-            })?;
-            if &p.name != old_name {
-                anyhow::bail!(
-                    "crate name in .crate ('{}') file did not match given name ('{}')",
-                    p.name,
-                    old_name
-                );
-            }
-            p.name = new_name.to_string();
+            let targets = edit_manifest(&mut manifest)?;
+
+            let got_canonical_cargo_toml = file_name.as_deref() == Some("Cargo.toml");
 
             // Work around https://gitlab.com/crates.rs/cargo_toml/-/issues/3
             // See https://github.com/alexcrichton/toml-rs/issues/142#issuecomment-278970591
@@ -191,63 +665,120 @@ pub fn dot_crate(
             let mut bytes = &bytes[..]; // to give us io::Read
             header.set_size(bytes.len() as u64);
             header.set_cksum();
-            repackaged
+            builder
                 .append_data(&mut header, path, &mut bytes)
                 .context("append modified Cargo.toml to new .crate file")?;
 
-            got_cargo_toml = true;
-        } else if !path.starts_with("src") && path.extension().map(|e| e == "rs").unwrap_or(false) {
-            // Replace previous_crate_name with new_crate_name.
-            //
-            // Binaries, tests, etc. will contain previous_crate_name:: paths, which we need to
-            // re-write so that they still work after we change the top-level crate name. We
-            // _could_ try to inject `extern crate previous_crate_name as new_crate_name`, but it
-            // gets tricky as those can only be injected at the top-level crate entry point (and
-            // only after //!, #!, /*!, etc.), so just straight up replacing is easier.
-            //
-            // Now, _technically_ this replacement shouldn't matter, since we're modifying a
-            // package in a `.crate`, so any consumers should only be using the `lib` of the
-            // current package anyway. And `lib` lives in `src/` (let's go ahead and assume they
-            // haven't changed that) and refers to the current crate using `::` or `crate::`,
-            // neither of which contain the current crate's name.
-            //
-            // But, we go for best effort anyway.
-
-            // It would be nice if we could do the rewrite in a streaming fashion.
-            // Unfortunately, doing so is tricky for two main reasons:
+            if got_canonical_cargo_toml {
+                got_cargo_toml = true;
+                renamed_targets = targets;
+            }
+        } else if path.extension().map(|e| e == "rs").unwrap_or(false) && rewrite_rs(sub_path) {
+            // It would be nice if we could do the rewrite in a streaming fashion. Unfortunately,
+            // doing so is tricky for two main reasons:
             //
-            //  1) replacing the crate name changes the file size. We have to declare the size in
-            //     the header, but we don't know how many replacements we're going to do until
-            //     we've passed over the data!
-            //  2) the crate name may appear at a chunk boundary.
+            //  1) rewriting can change the file size. We have to declare the size in the header,
+            //     but we don't know the new size until we've passed over the data!
+            //  2) a path head may appear at a chunk boundary.
             //
-            // So, we just read the file into memory and then do the replacement(s) there.
+            // So, we just read the file into memory and then do the rewrite there.
             file_bytes.clear();
             file.read_to_string(&mut file_bytes)
                 .context("read .rs file for in-place modification")?;
 
-            let file_bytes = if file_bytes.contains(&from) {
-                std::borrow::Cow::Owned(file_bytes.replace(&from, &to))
-            } else {
-                std::borrow::Cow::Borrowed(&file_bytes)
-            };
+            let rewritten = rewrite::rewrite_path_heads(&file_bytes, old_ident, new_ident)
+                .with_context(|| format!("rewrite path heads in {}", path.display()))?;
 
-            header.set_size(file_bytes.bytes().len() as u64);
-            repackaged.append_data(&mut header, path, &mut file_bytes.as_bytes())?;
+            if rewritten.count > 0 {
+                rewritten_files.push((path.clone(), rewritten.count));
+            }
+
+            header.set_size(rewritten.source.bytes().len() as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, path, &mut rewritten.source.as_bytes())?;
         } else {
-            repackaged
+            builder
                 .append_data(&mut header, path, file)
                 .context("append unmodified file to new .crate file")?;
         }
     }
 
-    if !got_cargo_toml {
-        let _ = std::fs::remove_file(repackaged_path);
+    Ok(ArchiveSummary {
+        got_cargo_toml,
+        renamed_targets,
+        rewritten_files,
+    })
+}
+
+/// Rewrite `product`'s explicit `name` to `new_name` if it currently names `old_name` (modulo
+/// `-`/`_`), reporting whether it did. A target with no explicit name defaults to `package.name`,
+/// which has already been renamed by the time this runs, so it's left alone here.
+fn rename_target(product: &mut cargo_toml::Product, old_name: &str, new_name: &str) -> bool {
+    let old_ident = old_name.replace('-', "_");
+    if product.name.as_deref().map(|n| n.replace('-', "_")) == Some(old_ident) {
+        // Target names are identifiers, not crate names, so Cargo rejects a hyphenated one here
+        // even though it accepts one in `package.name`.
+        product.name = Some(new_name.replace('-', "_"));
+        true
+    } else {
+        false
+    }
+}
+
+/// Infer the crate's current name from its `.crate` file name (`{name}-{version}.crate`). If
+/// `given` is `Some`, it's checked against the inferred name instead of being trusted blindly, so
+/// that repackaging the wrong `.crate` file is caught early.
+fn infer_old_name<'a>(old_fn: &'a str, given: Option<&'a str>) -> anyhow::Result<&'a str> {
+    // Let's also handle the somewhat unlikely (but possible) prefix problem: Imagine someone
+    // wants to rewrite the net crate to net2, but then pass us the crate file:
+    //
+    //     netscape-0.1.0.crate
+    //
+    // Sure, it _starts_ with net, but it's probably not the .crate file they intended to pass us.
+    //
+    // The trick is to look for the first . (which cannot appear in crate names), and walk
+    // _backwards_ from there.
+    let mut prefix = None;
+    if let Some(dot) = old_fn.find('.') {
+        if let Some(dash) = old_fn[..dot].rfind('-') {
+            let name = &old_fn[..dash];
+            let major = &old_fn[(dash + 1)..dot];
+            if !name.is_empty() && !major.is_empty() && major.chars().all(|c| c.is_ascii_digit()) {
+                prefix = Some(name);
+            }
+        }
+    }
+    if given.is_some() && prefix != given {
         anyhow::bail!(
-            ".crate file {} did not contain a Cargo.toml file",
-            dot_crate_path.display()
+            ".crate file '{}' does not match given old name '{}'",
+            old_fn,
+            given.expect("checked is_some above"),
         );
     }
+    given
+        .or(prefix)
+        .ok_or_else(|| anyhow::anyhow!("failed to infer current crate name"))
+}
 
+/// Point `dep` at the given alternate registry `name` and/or `index`, promoting it from a bare
+/// version string to a detailed dependency if it isn't one already.
+fn set_registry(
+    dep: &mut cargo_toml::Dependency,
+    name: Option<&str>,
+    index: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut detail = match std::mem::replace(dep, cargo_toml::Dependency::Simple(String::new())) {
+        cargo_toml::Dependency::Simple(version) => cargo_toml::DependencyDetail {
+            version: Some(version),
+            ..Default::default()
+        },
+        cargo_toml::Dependency::Detailed(detail) => *detail,
+        cargo_toml::Dependency::Inherited(_) => anyhow::bail!(
+            "dependency inherits from the workspace, so it has no version/registry of its own to retarget"
+        ),
+    };
+    detail.registry = name.map(str::to_string);
+    detail.registry_index = index.map(str::to_string);
+    *dep = cargo_toml::Dependency::Detailed(Box::new(detail));
     Ok(())
 }
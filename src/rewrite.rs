@@ -0,0 +1,238 @@
+//! Token-aware rewriting of path-head references to a crate name.
+//!
+//! A plain string or substring replacement can't tell a crate name used as a path head
+//! (`old::Foo`, `use old::bar`, `extern crate old;`) apart from the same identifier showing up as
+//! a struct field, a method receiver, or a substring of some other identifier. This module walks
+//! the source as a [`proc_macro2::TokenStream`] instead, so only identifiers that are actually in
+//! path-head position get rewritten, and everything else -- whitespace, comments, formatting --
+//! is left byte-for-byte untouched.
+
+use proc_macro2::{LineColumn, Spacing, TokenStream, TokenTree};
+use std::str::FromStr;
+
+/// The result of rewriting path-head references to a crate name in a source file.
+pub(crate) struct Rewritten {
+    /// The source with all path-head references replaced.
+    pub(crate) source: String,
+    /// How many path-head identifiers were rewritten.
+    pub(crate) count: usize,
+}
+
+/// Rewrite every path-head occurrence of `old_name` to `new_name` in `src`.
+///
+/// An identifier is considered to be in path-head position if it is immediately followed by a
+/// `::` path separator and is neither a later segment of a longer path (`crate::old::`) nor a
+/// method name (`x.old::<T>()`), or if it immediately follows the `extern crate` or `use`
+/// keywords. Both
+/// `old_name` and `new_name` are compared/substituted verbatim, so callers should normalize `-` to
+/// `_` first, same as Rust does when turning a crate name into its library identifier.
+pub(crate) fn rewrite_path_heads(
+    src: &str,
+    old_name: &str,
+    new_name: &str,
+) -> anyhow::Result<Rewritten> {
+    let tokens =
+        TokenStream::from_str(src).map_err(|e| anyhow::anyhow!("failed to tokenize source: {}", e))?;
+
+    let mut spans = Vec::new();
+    collect_path_heads(tokens, old_name, &mut spans);
+
+    if spans.is_empty() {
+        return Ok(Rewritten {
+            source: src.to_string(),
+            count: 0,
+        });
+    }
+    spans.sort_by_key(|(start, _)| (start.line, start.column));
+
+    let line_starts = line_starts(src);
+    let mut source = String::with_capacity(src.len());
+    let mut cursor = 0;
+    for (start, end) in &spans {
+        let start = byte_offset(src, &line_starts, *start);
+        let end = byte_offset(src, &line_starts, *end);
+        source.push_str(&src[cursor..start]);
+        source.push_str(new_name);
+        cursor = end;
+    }
+    source.push_str(&src[cursor..]);
+
+    Ok(Rewritten {
+        source,
+        count: spans.len(),
+    })
+}
+
+/// Walk `tokens`, recursing into groups, and record the span of every `Ident` equal to
+/// `old_name` that sits in path-head position.
+fn collect_path_heads(tokens: TokenStream, old_name: &str, spans: &mut Vec<(LineColumn, LineColumn)>) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+
+    // The two significant tokens (idents, puncts, literals) we've seen so far at this nesting
+    // level, most recent first. Entering a group starts a fresh lookback, since whatever came
+    // before the delimiter can never make the first token inside it a path head.
+    let mut prev1: Option<String> = None;
+    let mut prev2: Option<String> = None;
+
+    for (i, tt) in tokens.iter().enumerate() {
+        match tt {
+            TokenTree::Group(group) => {
+                collect_path_heads(group.stream(), old_name, spans);
+                prev2 = prev1.take();
+                prev1 = None;
+            }
+            TokenTree::Ident(ident) => {
+                let text = ident.to_string();
+                if text == old_name {
+                    let followed_by_path_sep = matches!(
+                        tokens.get(i + 1),
+                        Some(TokenTree::Punct(p)) if p.as_char() == ':' && p.spacing() == Spacing::Joint
+                    );
+                    // An ident immediately preceded by `::` is a continuation of some other
+                    // path (e.g. the `old` in `crate::old::bar()`), not a path head, even
+                    // though it's also followed by `::`. Only the first segment of a path is
+                    // ever the crate name. But a *leading* `::` (an absolute path like
+                    // `::old::Foo::new()`) has no path segment before it, so it must not be
+                    // mistaken for a continuation -- hence also requiring an ident before the
+                    // `::` that can be a path segment (not a keyword like `use` or `return`).
+                    let preceded_by_path_sep = i >= 3
+                        && matches!(&tokens[i - 1], TokenTree::Punct(p) if p.as_char() == ':')
+                        && matches!(
+                            &tokens[i - 2],
+                            TokenTree::Punct(p) if p.as_char() == ':' && p.spacing() == Spacing::Joint
+                        )
+                        && matches!(
+                            &tokens[i - 3],
+                            TokenTree::Ident(seg) if !matches!(
+                                seg.to_string().as_str(),
+                                "as" | "break" | "const" | "continue" | "dyn" | "else" | "enum"
+                                    | "extern" | "fn" | "for" | "if" | "impl" | "in" | "let"
+                                    | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref"
+                                    | "return" | "static" | "struct" | "trait" | "type"
+                                    | "unsafe" | "use" | "where" | "while" | "yield"
+                            )
+                        );
+                    // A method name called with a turbofish (`x.old::<T>()`) is also followed
+                    // by `::`, but follows a single `.` (a `..` range is two `.` tokens).
+                    let method_name = prev1.as_deref() == Some(".") && prev2.as_deref() != Some(".");
+                    let after_use = prev1.as_deref() == Some("use");
+                    let after_extern_crate =
+                        prev1.as_deref() == Some("crate") && prev2.as_deref() == Some("extern");
+
+                    if (followed_by_path_sep && !preceded_by_path_sep && !method_name)
+                        || after_use
+                        || after_extern_crate
+                    {
+                        spans.push((ident.span().start(), ident.span().end()));
+                    }
+                }
+                prev2 = prev1.take();
+                prev1 = Some(text);
+            }
+            TokenTree::Punct(p) => {
+                prev2 = prev1.take();
+                prev1 = Some(p.as_char().to_string());
+            }
+            TokenTree::Literal(l) => {
+                prev2 = prev1.take();
+                prev1 = Some(l.to_string());
+            }
+        }
+    }
+}
+
+/// The byte offset of the start of each (1-indexed) line in `src`.
+fn line_starts(src: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(src.match_indices('\n').map(|(i, _)| i + 1));
+    starts
+}
+
+/// Convert a [`LineColumn`] (1-indexed line, 0-indexed *character* column) into a byte offset
+/// into `src`.
+fn byte_offset(src: &str, line_starts: &[usize], pos: LineColumn) -> usize {
+    let line_start = line_starts[pos.line - 1];
+    let line = &src[line_start..];
+    line.char_indices()
+        .nth(pos.column)
+        .map(|(byte_idx, _)| line_start + byte_idx)
+        .unwrap_or(line_start + line.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(src: &str) -> String {
+        rewrite_path_heads(src, "old", "new").unwrap().source
+    }
+
+    fn count(src: &str) -> usize {
+        rewrite_path_heads(src, "old", "new").unwrap().count
+    }
+
+    #[test]
+    fn path_head_is_rewritten() {
+        assert_eq!(rewrite("old::Foo::new()"), "new::Foo::new()");
+    }
+
+    #[test]
+    fn leading_macro_path_is_rewritten() {
+        // Tabs and a path-qualified macro invocation at the top of a block both tripped up the
+        // old `contains`/`replace`-based approach.
+        assert_eq!(
+            rewrite("fn main() {\n\told::vec![1, 2];\n}"),
+            "fn main() {\n\tnew::vec![1, 2];\n}"
+        );
+    }
+
+    #[test]
+    fn path_as_fn_argument_is_rewritten() {
+        assert_eq!(rewrite("iter.any(old::is_foo)"), "iter.any(new::is_foo)");
+    }
+
+    #[test]
+    fn use_statement_is_rewritten() {
+        assert_eq!(rewrite("use old::bar;"), "use new::bar;");
+    }
+
+    #[test]
+    fn extern_crate_is_rewritten() {
+        assert_eq!(rewrite("extern crate old;"), "extern crate new;");
+    }
+
+    #[test]
+    fn extern_crate_alias_keeps_alias() {
+        assert_eq!(
+            rewrite("extern crate old as aliased;"),
+            "extern crate new as aliased;"
+        );
+    }
+
+    #[test]
+    fn struct_field_is_untouched() {
+        assert_eq!(count("struct S { old: i32 }"), 0);
+    }
+
+    #[test]
+    fn method_receiver_is_untouched() {
+        assert_eq!(count("fn f(old: S) { old.bar(); }"), 0);
+    }
+
+    #[test]
+    fn path_continuation_is_untouched() {
+        // `old` here is a local submodule that happens to share the crate's name, not the crate
+        // itself -- only the head of a path is ever the crate name, so the second segment must
+        // be left alone even though it's also followed by `::`.
+        assert_eq!(count("crate::old::bar()"), 0);
+        assert_eq!(rewrite("crate::old::bar()"), "crate::old::bar()");
+    }
+
+    #[test]
+    fn leading_absolute_path_is_rewritten() {
+        // Unlike `path_continuation_is_untouched`, the `::` here has nothing before it, so `old`
+        // is still the head of the path and must be rewritten.
+        assert_eq!(count("::old::Foo::new()"), 1);
+        assert_eq!(rewrite("::old::Foo::new()"), "::new::Foo::new()");
+    }
+}
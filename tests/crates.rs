@@ -107,3 +107,235 @@ fn trivial() {
 fn with_tests() {
     let (_manifest, _unpkg) = repackage!("with-tests", "wt");
 }
+
+#[test]
+fn cargo_toml_orig_is_rewritten() {
+    // `repackage!` already checks the normalized `Cargo.toml`; `Cargo.toml.orig` (the verbatim
+    // pre-publish manifest `cargo package` also ships) needs the same edits, or anything that
+    // reads it -- `cargo package`'s own re-verification included -- would still see the old name.
+    let (_manifest, unpkg) = repackage!("trivial", "rptest2");
+
+    let cargo_toml_orig = unpkg.join("Cargo.toml.orig");
+    let cargo_toml_orig =
+        std::fs::read(&cargo_toml_orig).expect("failed to read repackaged Cargo.toml.orig");
+    let manifest = Manifest::from_slice(&cargo_toml_orig)
+        .expect("parse Cargo.toml.orig from repackaged .crate file");
+    assert_eq!(
+        manifest
+            .package
+            .as_ref()
+            .expect("repackaged Cargo.toml.orig has no package")
+            .name,
+        "rptest2"
+    );
+}
+
+#[test]
+fn repackaged_tar_headers_have_valid_checksums() {
+    let dot_crate = Path::new("tests/test-crates/trivial/target/package/trivial-0.1.0.crate");
+    let output = Command::new("cargo")
+        .arg("package")
+        .arg("--quiet")
+        .arg("--allow-dirty")
+        .arg("--no-verify")
+        .arg("--no-metadata")
+        .current_dir("tests/test-crates/trivial")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .expect("failed to run cargo package");
+    assert!(output.status.success(), "cargo package failed: {:?}", output);
+
+    repackage::dot_crate(dot_crate, Some("trivial"), "rptest3").expect("repackaging failed");
+
+    let new_dot_crate =
+        Path::new("tests/test-crates/trivial/target/package/rptest3-0.1.0.crate");
+    let dot_crate =
+        std::fs::File::open(new_dot_crate).expect("could not open repackaged .crate");
+    let dot_crate = flate2::read::GzDecoder::new(dot_crate);
+    let mut dot_crate = tar::Archive::new(dot_crate);
+
+    for entry in dot_crate.entries().expect("failed to walk entries") {
+        let entry = entry.expect("failed to walk entry");
+        let header = entry.header();
+        let stored = header.cksum().expect("entry header has no checksum");
+        let mut recomputed = header.clone();
+        recomputed.set_cksum();
+        assert_eq!(
+            recomputed.cksum().expect("recomputed header has no checksum"),
+            stored,
+            "tar header checksum does not match its (possibly resized) entry"
+        );
+    }
+}
+
+#[test]
+fn rename_dependency_patches_consumer() {
+    // `rename_dependency` doesn't rename the package itself -- it patches references to some
+    // *other* crate that the package depends on, e.g. after that crate was itself repackaged.
+    let output = Command::new("cargo")
+        .arg("package")
+        .arg("--quiet")
+        .arg("--allow-dirty")
+        .arg("--no-verify")
+        .arg("--no-metadata")
+        .current_dir("tests/test-crates/depends-on-trivial")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .expect("failed to run cargo package");
+    assert!(output.status.success(), "cargo package failed: {:?}", output);
+
+    let dot_crate = Path::new(
+        "tests/test-crates/depends-on-trivial/target/package/depends-on-trivial-0.1.0.crate",
+    );
+    repackage::rename_dependency(dot_crate, "trivial", "rptest")
+        .expect("renaming dependency failed");
+
+    let patched_dot_crate = Path::new(
+        "tests/test-crates/depends-on-trivial/target/package/depends-on-trivial-0.1.0-patched.crate",
+    );
+    assert!(
+        patched_dot_crate.exists(),
+        "{} does not exist after rename_dependency",
+        patched_dot_crate.display()
+    );
+
+    let dot_crate =
+        std::fs::File::open(patched_dot_crate).expect("could not open patched .crate");
+    let dot_crate = flate2::read::GzDecoder::new(dot_crate);
+    let mut dot_crate = tar::Archive::new(dot_crate);
+
+    let unpkg = Path::new("tests/test-crates/depends-on-trivial/target/unpackage-patched");
+    if unpkg.exists() {
+        std::fs::remove_dir_all(unpkg).expect("failed to remove old unpackage dir");
+    }
+    std::fs::create_dir_all(unpkg).expect("failed to create unpackage dir");
+    dot_crate
+        .unpack(unpkg)
+        .expect("failed to unpackage patched .crate");
+
+    let unpkg = unpkg.join("depends-on-trivial-0.1.0");
+    let cargo_toml = unpkg.join("Cargo.toml");
+    let cargo_toml = std::fs::read(&cargo_toml).expect("failed to read patched Cargo.toml");
+    let manifest =
+        Manifest::from_slice(&cargo_toml).expect("parse Cargo.toml from patched .crate file");
+
+    assert!(
+        !manifest.dependencies.contains_key("trivial"),
+        "patched manifest still depends on trivial"
+    );
+    assert!(
+        manifest.dependencies.contains_key("rptest"),
+        "patched manifest does not depend on rptest"
+    );
+}
+
+#[test]
+fn repackage_can_bump_version_and_registry() {
+    let output = Command::new("cargo")
+        .arg("package")
+        .arg("--quiet")
+        .arg("--allow-dirty")
+        .arg("--no-verify")
+        .arg("--no-metadata")
+        .current_dir("tests/test-crates/trivial")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .expect("failed to run cargo package");
+    assert!(output.status.success(), "cargo package failed: {:?}", output);
+
+    let dot_crate = Path::new("tests/test-crates/trivial/target/package/trivial-0.1.0.crate");
+    repackage::Repackage::new("rptest4")
+        .old_name("trivial")
+        .version("0.2.0")
+        .registry(Some("my-registry"), None::<&str>)
+        .run(dot_crate)
+        .expect("repackaging with Repackage builder failed");
+
+    let new_dot_crate = Path::new("tests/test-crates/trivial/target/package/rptest4-0.2.0.crate");
+    assert!(
+        new_dot_crate.exists(),
+        "{} does not exist after Repackage::run",
+        new_dot_crate.display()
+    );
+
+    let dot_crate =
+        std::fs::File::open(new_dot_crate).expect("could not open repackaged .crate");
+    let dot_crate = flate2::read::GzDecoder::new(dot_crate);
+    let mut dot_crate = tar::Archive::new(dot_crate);
+
+    let unpkg = Path::new("tests/test-crates/trivial/target/unpackage-bumped");
+    if unpkg.exists() {
+        std::fs::remove_dir_all(unpkg).expect("failed to remove old unpackage dir");
+    }
+    std::fs::create_dir_all(unpkg).expect("failed to create unpackage dir");
+    dot_crate
+        .unpack(unpkg)
+        .expect("failed to unpackage repackaged .crate");
+
+    let unpkg = unpkg.join("rptest4-0.2.0");
+    let cargo_toml = unpkg.join("Cargo.toml");
+    let cargo_toml = std::fs::read(&cargo_toml).expect("failed to read repackaged Cargo.toml");
+    let manifest =
+        Manifest::from_slice(&cargo_toml).expect("parse Cargo.toml from repackaged .crate file");
+
+    let package = manifest
+        .package
+        .as_ref()
+        .expect("repackaged manifest has no package");
+    assert_eq!(package.name, "rptest4");
+    assert_eq!(
+        package.version.get().expect("version is workspace-inherited"),
+        "0.2.0"
+    );
+}
+
+#[test]
+fn dot_crate_stream_reports_renames() {
+    let output = Command::new("cargo")
+        .arg("package")
+        .arg("--quiet")
+        .arg("--allow-dirty")
+        .arg("--no-verify")
+        .arg("--no-metadata")
+        .current_dir("tests/test-crates/trivial")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .expect("failed to run cargo package");
+    assert!(output.status.success(), "cargo package failed: {:?}", output);
+
+    let input = std::fs::File::open("tests/test-crates/trivial/target/package/trivial-0.1.0.crate")
+        .expect("could not open original .crate");
+    let mut output = Vec::new();
+    let report = repackage::dot_crate_stream(input, &mut output, "trivial", "rptest5")
+        .expect("dot_crate_stream failed");
+
+    assert_eq!(report.old_name, "trivial");
+    assert_eq!(report.new_name, "rptest5");
+    assert_eq!(report.old_base_dir, Path::new("trivial-0.1.0"));
+    assert_eq!(report.new_base_dir, Path::new("rptest5-0.1.0"));
+
+    let dot_crate = flate2::read::GzDecoder::new(&output[..]);
+    let mut dot_crate = tar::Archive::new(dot_crate);
+
+    let unpkg = Path::new("tests/test-crates/trivial/target/unpackage-stream");
+    if unpkg.exists() {
+        std::fs::remove_dir_all(unpkg).expect("failed to remove old unpackage dir");
+    }
+    std::fs::create_dir_all(unpkg).expect("failed to create unpackage dir");
+    dot_crate
+        .unpack(unpkg)
+        .expect("failed to unpackage streamed .crate");
+
+    let cargo_toml = unpkg.join("rptest5-0.1.0").join("Cargo.toml");
+    let cargo_toml = std::fs::read(&cargo_toml).expect("failed to read streamed Cargo.toml");
+    let manifest =
+        Manifest::from_slice(&cargo_toml).expect("parse Cargo.toml from streamed .crate file");
+    assert_eq!(
+        manifest
+            .package
+            .as_ref()
+            .expect("streamed manifest has no package")
+            .name,
+        "rptest5"
+    );
+}